@@ -1,86 +1,611 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::{env, process};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 enum Operand {
     Value(i32),
     Var(String),
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 enum Instruction {
     Push(i32),
-    Add(Operand, Operand),
-    Sub(Operand, Operand),
-    Mul(Operand, Operand),
-    Div(Operand, Operand),
+    Add,
+    Sub,
+    Mul,
+    Div,
     Print,
     Set(String, i32),
     Get(String),
     Input(String),
-    If(Vec<Instruction>, Vec<Instruction>),
-    Else(Vec<Instruction>),
+    Label(String),
+    Jmp(String),
+    Jz(String),
+    Jnz(String),
+    Pop,
+    Dup,
+    Swap,
+    Over,
+    And(Operand, Operand),
+    Or(Operand, Operand),
+    Xor(Operand, Operand),
+    Nand(Operand, Operand),
+    Shl(Operand, Operand),
+    Shr(Operand, Operand),
+    Eq(Operand, Operand),
+    Lt(Operand, Operand),
+    Gt(Operand, Operand),
+    Call(String),
+    Ret,
 }
 
+/// EVM-style view over a stack, with checked access by depth from the top
+/// (`peek(0)` is the top) instead of raw `Vec` indices.
+trait Stack<T> {
+    /// The item `n` slots down from the top (`peek(0)` is the top itself).
+    fn peek(&self, n: usize) -> Option<&T>;
+    /// Removes and returns the top item.
+    fn pop_back(&mut self) -> Option<T>;
+    /// Removes and returns the top `k` items, in their original bottom-to-top order.
+    fn pop_n(&mut self, k: usize) -> Option<Vec<T>>;
+    /// Swaps the top item with the one `n` slots below it.
+    fn swap_with_top(&mut self, n: usize) -> bool;
+    /// Whether at least `k` items are on the stack.
+    fn has(&self, k: usize) -> bool;
+}
+
+impl<T> Stack<T> for Vec<T> {
+    fn peek(&self, n: usize) -> Option<&T> {
+        self.len().checked_sub(n + 1).and_then(|i| self.get(i))
+    }
+
+    fn pop_back(&mut self) -> Option<T> {
+        self.pop()
+    }
+
+    fn pop_n(&mut self, k: usize) -> Option<Vec<T>> {
+        if !self.has(k) {
+            return None;
+        }
+        Some(self.split_off(self.len() - k))
+    }
+
+    fn swap_with_top(&mut self, n: usize) -> bool {
+        let len = self.len();
+        if n == 0 || n >= len {
+            return false;
+        }
+        self.swap(len - 1, len - 1 - n);
+        true
+    }
+
+    fn has(&self, k: usize) -> bool {
+        self.len() >= k
+    }
+}
+
+/// A loaded program: its flat instruction stream plus the resolved index of
+/// every `Label` in it, so `VM::run` can jump by index instead of re-reading
+/// source.
+struct Program {
+    instructions: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+}
+
+/// Resolves every `Label` in `instructions` to its index, producing a
+/// `Program` that `VM::run` can execute with direct `pc` jumps.
+fn build_program(instructions: Vec<Instruction>) -> Program {
+    let mut labels = HashMap::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        if let Instruction::Label(name) = instruction {
+            labels.insert(name.clone(), i);
+        }
+    }
+    Program { instructions, labels }
+}
+
+// --- Bytecode ---
+//
+// A fixed-width binary encoding of a `Vec<Instruction>`: a magic/version
+// header, a constant table holding every variable and label name, then one
+// opcode byte per instruction followed by its operand payload. Variable and
+// label names are referenced by `u16` index into the constant table, so a
+// name operand is always two bytes rather than an inline string. Each operand
+// of the two-operand instructions (`AND`, `EQ`, ...) carries a tag byte
+// followed by either an `i32` value or a `u16` name index, so their size
+// depends on those tags. This skips re-parsing text on every run and
+// produces a small, distributable artifact.
+
+const BYTECODE_MAGIC: &[u8; 4] = b"RVMC";
+const BYTECODE_VERSION: u8 = 1;
+
+/// Interns strings in first-seen order so they can be referenced by a
+/// compact `u16` index in the encoded instruction stream.
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, u16>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable {
+            strings: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn intern(&mut self, s: &str) -> u16 {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u16;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), i);
+        i
+    }
+}
+
+fn opcode_of(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::Push(_) => 0,
+        Instruction::Add => 1,
+        Instruction::Sub => 2,
+        Instruction::Mul => 3,
+        Instruction::Div => 4,
+        Instruction::Print => 5,
+        Instruction::Set(_, _) => 6,
+        Instruction::Get(_) => 7,
+        Instruction::Input(_) => 8,
+        Instruction::Label(_) => 9,
+        Instruction::Jmp(_) => 10,
+        Instruction::Jz(_) => 11,
+        Instruction::Jnz(_) => 12,
+        Instruction::Pop => 13,
+        Instruction::Dup => 14,
+        Instruction::Swap => 15,
+        Instruction::Over => 16,
+        Instruction::And(_, _) => 17,
+        Instruction::Or(_, _) => 18,
+        Instruction::Xor(_, _) => 19,
+        Instruction::Nand(_, _) => 20,
+        Instruction::Shl(_, _) => 21,
+        Instruction::Shr(_, _) => 22,
+        Instruction::Eq(_, _) => 23,
+        Instruction::Lt(_, _) => 24,
+        Instruction::Gt(_, _) => 25,
+        Instruction::Call(_) => 26,
+        Instruction::Ret => 27,
+    }
+}
+
+fn encode_operand(operand: &Operand, constants: &mut StringTable, out: &mut Vec<u8>) {
+    match operand {
+        Operand::Value(value) => {
+            out.push(0);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        Operand::Var(name) => {
+            out.push(1);
+            out.extend_from_slice(&constants.intern(name).to_le_bytes());
+        }
+    }
+}
+
+/// Serializes `instructions` into the compact binary bytecode format
+/// (magic header, constant table, then one opcode + payload per
+/// instruction).
+fn compile_program(instructions: &[Instruction]) -> Vec<u8> {
+    let mut constants = StringTable::new();
+    let mut body = Vec::new();
+
+    for instruction in instructions {
+        body.push(opcode_of(instruction));
+        match instruction {
+            Instruction::Push(value) => body.extend_from_slice(&value.to_le_bytes()),
+            Instruction::And(a, b)
+            | Instruction::Or(a, b)
+            | Instruction::Xor(a, b)
+            | Instruction::Nand(a, b)
+            | Instruction::Shl(a, b)
+            | Instruction::Shr(a, b)
+            | Instruction::Eq(a, b)
+            | Instruction::Lt(a, b)
+            | Instruction::Gt(a, b) => {
+                encode_operand(a, &mut constants, &mut body);
+                encode_operand(b, &mut constants, &mut body);
+            }
+            Instruction::Print
+            | Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Pop
+            | Instruction::Dup
+            | Instruction::Swap
+            | Instruction::Over
+            | Instruction::Ret => {}
+            Instruction::Set(name, value) => {
+                body.extend_from_slice(&constants.intern(name).to_le_bytes());
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+            Instruction::Get(name)
+            | Instruction::Input(name)
+            | Instruction::Label(name)
+            | Instruction::Jmp(name)
+            | Instruction::Jz(name)
+            | Instruction::Jnz(name)
+            | Instruction::Call(name) => {
+                body.extend_from_slice(&constants.intern(name).to_le_bytes());
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(BYTECODE_MAGIC);
+    out.push(BYTECODE_VERSION);
+    out.extend_from_slice(&(constants.strings.len() as u16).to_le_bytes());
+    for s in &constants.strings {
+        out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+    out.extend_from_slice(&(instructions.len() as u32).to_le_bytes());
+    out.extend(body);
+    out
+}
+
+/// Reads bytes sequentially out of a bytecode buffer, reporting
+/// `RunError::InvalidInput` on truncation instead of panicking.
+struct ByteCursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteCursor<'a> {
+    fn new(bytes: &'a [u8]) -> ByteCursor<'a> {
+        ByteCursor { bytes, pos: 0 }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], RunError> {
+        let end = self.pos + n;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or_else(|| RunError::InvalidInput("unexpected end of bytecode".to_string()))?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RunError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, RunError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, RunError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, RunError> {
+        Ok(self.read_u32()? as i32)
+    }
+}
+
+fn decode_operand(cursor: &mut ByteCursor, constants: &[String]) -> Result<Operand, RunError> {
+    match cursor.read_u8()? {
+        0 => Ok(Operand::Value(cursor.read_i32()?)),
+        1 => Ok(Operand::Var(lookup_constant(constants, cursor.read_u16()?)?)),
+        tag => Err(RunError::InvalidInput(format!("unknown operand tag: {}", tag))),
+    }
+}
+
+fn lookup_constant(constants: &[String], index: u16) -> Result<String, RunError> {
+    constants
+        .get(index as usize)
+        .cloned()
+        .ok_or_else(|| RunError::InvalidInput(format!("constant index out of range: {}", index)))
+}
+
+/// Reconstructs the `Vec<Instruction>` previously produced by
+/// `compile_program`.
+fn load_bytecode(bytes: &[u8]) -> Result<Vec<Instruction>, RunError> {
+    let mut cursor = ByteCursor::new(bytes);
+
+    if cursor.read_bytes(BYTECODE_MAGIC.len())? != BYTECODE_MAGIC {
+        return Err(RunError::InvalidInput("not a rustvm bytecode file".to_string()));
+    }
+    let version = cursor.read_u8()?;
+    if version != BYTECODE_VERSION {
+        return Err(RunError::InvalidInput(format!("unsupported bytecode version: {}", version)));
+    }
+
+    let const_count = cursor.read_u16()?;
+    let mut constants = Vec::with_capacity(const_count as usize);
+    for _ in 0..const_count {
+        let len = cursor.read_u16()? as usize;
+        let bytes = cursor.read_bytes(len)?;
+        let s = String::from_utf8(bytes.to_vec())
+            .map_err(|_| RunError::InvalidInput("bytecode constant is not valid utf-8".to_string()))?;
+        constants.push(s);
+    }
+
+    let instruction_count = cursor.read_u32()?;
+    let mut instructions = Vec::with_capacity(instruction_count as usize);
+    for _ in 0..instruction_count {
+        let instruction = match cursor.read_u8()? {
+            0 => Instruction::Push(cursor.read_i32()?),
+            1 => Instruction::Add,
+            2 => Instruction::Sub,
+            3 => Instruction::Mul,
+            4 => Instruction::Div,
+            5 => Instruction::Print,
+            6 => {
+                let name = lookup_constant(&constants, cursor.read_u16()?)?;
+                Instruction::Set(name, cursor.read_i32()?)
+            }
+            7 => Instruction::Get(lookup_constant(&constants, cursor.read_u16()?)?),
+            8 => Instruction::Input(lookup_constant(&constants, cursor.read_u16()?)?),
+            9 => Instruction::Label(lookup_constant(&constants, cursor.read_u16()?)?),
+            10 => Instruction::Jmp(lookup_constant(&constants, cursor.read_u16()?)?),
+            11 => Instruction::Jz(lookup_constant(&constants, cursor.read_u16()?)?),
+            12 => Instruction::Jnz(lookup_constant(&constants, cursor.read_u16()?)?),
+            13 => Instruction::Pop,
+            14 => Instruction::Dup,
+            15 => Instruction::Swap,
+            16 => Instruction::Over,
+            17 => Instruction::And(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            18 => Instruction::Or(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            19 => Instruction::Xor(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            20 => Instruction::Nand(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            21 => Instruction::Shl(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            22 => Instruction::Shr(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            23 => Instruction::Eq(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            24 => Instruction::Lt(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            25 => Instruction::Gt(decode_operand(&mut cursor, &constants)?, decode_operand(&mut cursor, &constants)?),
+            26 => Instruction::Call(lookup_constant(&constants, cursor.read_u16()?)?),
+            27 => Instruction::Ret,
+            opcode => return Err(RunError::InvalidInput(format!("unknown opcode: {}", opcode))),
+        };
+        instructions.push(instruction);
+    }
+
+    Ok(instructions)
+}
+
+/// Errors that can occur while executing a loaded program.
+///
+/// These replace the panics that used to abort the whole interpreter, so a
+/// caller can report the failure (and, in tests, assert on the exact
+/// variant) instead of the process unwinding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RunError {
+    DivisionByZero,
+    UndefinedVariable(String),
+    StackUnderflow,
+    InvalidInput(String),
+    UnknownLabel(String),
+    StackOverflow,
+    OutOfGas,
+    CallStackOverflow,
+    ArithmeticOverflow,
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::DivisionByZero => write!(f, "division by zero"),
+            RunError::UndefinedVariable(name) => write!(f, "undefined variable: {}", name),
+            RunError::StackUnderflow => write!(f, "stack underflow"),
+            RunError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            RunError::UnknownLabel(name) => write!(f, "unknown label: {}", name),
+            RunError::StackOverflow => write!(f, "stack overflow"),
+            RunError::OutOfGas => write!(f, "ran out of instruction budget"),
+            RunError::CallStackOverflow => write!(f, "call stack overflow"),
+            RunError::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Upper bound on `VM::max_stack`, regardless of what a caller requests.
+const MAX_STACK_LIMIT: usize = 65535;
+
+/// Default `VM::max_stack` when none is configured.
+const DEFAULT_MAX_STACK: usize = 256;
+
+/// Maximum depth of the `CALL` return-address stack before `run` fails with
+/// `CallStackOverflow` (guards against unbounded/infinite recursion).
+const MAX_CALL_DEPTH: usize = 1024;
+
+#[derive(Debug)]
 struct VM {
     stack: Vec<i32>,
     vars: HashMap<String, i32>,
+    max_stack: usize,
+    /// Instructions left to dispatch before `run` returns `OutOfGas`.
+    /// `None` means unbounded.
+    steps_remaining: Option<u64>,
+    /// Return addresses pushed by `CALL` and popped by `RET`.
+    call_stack: Vec<usize>,
 }
 
 impl VM {
+    /// Convenience constructor for tests; the bin always goes through
+    /// `with_max_stack` so it can honor `--stack`.
+    #[cfg(test)]
     fn new() -> VM {
+        VM::with_max_stack(DEFAULT_MAX_STACK)
+    }
+
+    fn with_max_stack(max_stack: usize) -> VM {
         VM {
             stack: Vec::new(),
             vars: HashMap::new(),
+            max_stack: max_stack.min(MAX_STACK_LIMIT),
+            steps_remaining: None,
+            call_stack: Vec::new(),
+        }
+    }
+
+    /// Bounds how many instructions `run` will dispatch before failing with
+    /// `OutOfGas`, so an untrusted or buggy (infinitely looping) program
+    /// can't hang the interpreter.
+    fn with_max_steps(mut self, max_steps: u64) -> VM {
+        self.steps_remaining = Some(max_steps);
+        self
+    }
+
+    /// Pushes `value`, returning `StackOverflow` once `max_stack` is reached.
+    fn push(&mut self, value: i32) -> Result<(), RunError> {
+        if self.stack.len() >= self.max_stack {
+            return Err(RunError::StackOverflow);
         }
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn get_operand_value(&self, operand: &Operand) -> i32 {
+    fn get_operand_value(&self, operand: &Operand) -> Result<i32, RunError> {
         match operand {
-            Operand::Value(val) => *val,
-            Operand::Var(var_name) => *self.vars.get(var_name)
-                .expect("Variable not found"),
+            Operand::Value(val) => Ok(*val),
+            Operand::Var(var_name) => self
+                .vars
+                .get(var_name)
+                .copied()
+                .ok_or_else(|| RunError::UndefinedVariable(var_name.clone())),
         }
     }
 
-    fn run(&mut self, program: Vec<Instruction>, path: &str) {
+    fn run(&mut self, program: &Program) -> Result<(), RunError> {
         let mut pc = 0; // Program counter
-        while pc < program.len() {
-            match &program[pc] {
+        while pc < program.instructions.len() {
+            if let Some(steps) = self.steps_remaining.as_mut() {
+                *steps = steps.checked_sub(1).ok_or(RunError::OutOfGas)?;
+            }
+
+            match &program.instructions[pc] {
                 //PUSH
-                Instruction::Push(val) => self.stack.push(*val),
+                Instruction::Push(val) => self.push(*val)?,
 
-                //ADDITION
-                Instruction::Add(op1, op2) => {
-                    let val1 = self.get_operand_value(op1);
-                    let val2 = self.get_operand_value(op2);
-                    self.stack.push(val1 + val2);
+                //ADDITION: pops the top two values and pushes their sum (RPN-style)
+                Instruction::Add => {
+                    let operands = self.stack.pop_n(2).ok_or(RunError::StackUnderflow)?;
+                    let result = operands[0].checked_add(operands[1]).ok_or(RunError::ArithmeticOverflow)?;
+                    self.push(result)?;
                 },
 
-                //SUBSTRACTION
-                Instruction::Sub(op1, op2) => {
-                    let val1 = self.get_operand_value(op1);
-                    let val2 = self.get_operand_value(op2);
-                    self.stack.push(val1 - val2);
+                //SUBTRACTION: pops the top two values and pushes (second-from-top - top)
+                Instruction::Sub => {
+                    let operands = self.stack.pop_n(2).ok_or(RunError::StackUnderflow)?;
+                    let result = operands[0].checked_sub(operands[1]).ok_or(RunError::ArithmeticOverflow)?;
+                    self.push(result)?;
                 },
 
-                //MULTIPLICATION
-                Instruction::Mul(op1, op2) => {
-                    let val1 = self.get_operand_value(op1);
-                    let val2 = self.get_operand_value(op2);
-                    self.stack.push(val1 * val2);
+                //MULTIPLICATION: pops the top two values and pushes their product
+                Instruction::Mul => {
+                    let operands = self.stack.pop_n(2).ok_or(RunError::StackUnderflow)?;
+                    let result = operands[0].checked_mul(operands[1]).ok_or(RunError::ArithmeticOverflow)?;
+                    self.push(result)?;
                 },
 
-                //DIVISION
-                Instruction::Div(op1, op2) => {
-                    let val1 = self.get_operand_value(op1);
-                    let val2 = self.get_operand_value(op2);
-                    if val2 == 0 {
-                        panic!("Division by zero");
+                //DIVISION: pops the top two values and pushes (second-from-top / top)
+                Instruction::Div => {
+                    let operands = self.stack.pop_n(2).ok_or(RunError::StackUnderflow)?;
+                    if operands[1] == 0 {
+                        return Err(RunError::DivisionByZero);
                     }
-                    self.stack.push(val1 / val2);
+                    // i32::MIN / -1 overflows just like the other ops.
+                    let result = operands[0].checked_div(operands[1]).ok_or(RunError::ArithmeticOverflow)?;
+                    self.push(result)?;
+                },
+
+                //BITWISE AND
+                Instruction::And(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(val1 & val2)?;
+                },
+
+                //BITWISE OR
+                Instruction::Or(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(val1 | val2)?;
+                },
+
+                //BITWISE XOR
+                Instruction::Xor(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(val1 ^ val2)?;
+                },
+
+                //BITWISE NAND
+                Instruction::Nand(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(!(val1 & val2))?;
+                },
+
+                //SHIFT LEFT
+                Instruction::Shl(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(val1.wrapping_shl(val2 as u32))?;
+                },
+
+                //SHIFT RIGHT
+                Instruction::Shr(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push(val1.wrapping_shr(val2 as u32))?;
+                },
+
+                //EQUAL
+                Instruction::Eq(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push((val1 == val2) as i32)?;
+                },
+
+                //LESS THAN
+                Instruction::Lt(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push((val1 < val2) as i32)?;
+                },
+
+                //GREATER THAN
+                Instruction::Gt(op1, op2) => {
+                    let val1 = self.get_operand_value(op1)?;
+                    let val2 = self.get_operand_value(op2)?;
+                    self.push((val1 > val2) as i32)?;
+                },
+
+                //CALL A SUBROUTINE
+                Instruction::Call(label) => {
+                    let target = *program
+                        .labels
+                        .get(label)
+                        .ok_or_else(|| RunError::UnknownLabel(label.clone()))?;
+                    if self.call_stack.len() >= MAX_CALL_DEPTH {
+                        return Err(RunError::CallStackOverflow);
+                    }
+                    self.call_stack.push(pc + 1);
+                    pc = target;
+                    continue;
+                },
+
+                //RETURN FROM A SUBROUTINE
+                Instruction::Ret => {
+                    pc = self.call_stack.pop().ok_or(RunError::StackUnderflow)?;
+                    continue;
                 },
 
                 //PRINT
@@ -99,60 +624,93 @@ impl VM {
 
                 //GET VARIABLE
                 Instruction::Get(var_name) => {
-                    if let Some(&value) = self.vars.get(var_name) {
-                        self.stack.push(value);
-                    } else {
-                        panic!("Undefined variable: {}", var_name);
-                    }
+                    let value = *self
+                        .vars
+                        .get(var_name)
+                        .ok_or_else(|| RunError::UndefinedVariable(var_name.clone()))?;
+                    self.push(value)?;
                 },
 
                 //GET USER INPUT from the command line
                 Instruction::Input(var_name) => {
                     let mut input = String::new();
-                    io::stdin().read_line(&mut input).expect("Failed to read line");
-                    let value = input.trim().parse::<i32>().expect("Invalid input");
+                    io::stdin()
+                        .read_line(&mut input)
+                        .map_err(|e| RunError::InvalidInput(e.to_string()))?;
+                    let value = input
+                        .trim()
+                        .parse::<i32>()
+                        .map_err(|_| RunError::InvalidInput(input.trim().to_string()))?;
                     self.vars.insert(var_name.clone(), value);
                 },
 
-                //PROCESS IF instructions
-                Instruction::If(if_block, else_block) => {
-                    if let Some(top) = self.stack.last() {
-                        if *top != 0 {
-                            self.run(if_block.to_vec(), path); // IF the value at the stack is > 0, execute the IF instruction
-                        } else if !else_block.is_empty() { // If the value at the stack = 0, execute the else
-                            if let Ok(file) = File::open(path) {
-                                let reader = io::BufReader::new(file);
-                                let mut else_block_clone = else_block.clone(); // Clone the else_block
-                                let mut else_block_reader = reader.lines();
-
-                                for next_line in &mut else_block_reader {
-                                    if let Ok(next_line) = next_line {
-                                        else_block_clone.extend(parse_instruction(&next_line));
-                                    }
-                                }
-                                self.run(else_block_clone, path); // Pass the cloned else_block
-                            } else {
-                                panic!("Failed to open file: {}", path);
-                            }
-                        }
-                    } else {
-                        panic!("Stack is empty");
+                //LABEL (no-op marker, resolved ahead of time into `program.labels`)
+                Instruction::Label(_) => {},
+
+                //UNCONDITIONAL JUMP
+                Instruction::Jmp(label) => {
+                    pc = *program
+                        .labels
+                        .get(label)
+                        .ok_or_else(|| RunError::UnknownLabel(label.clone()))?;
+                    continue;
+                },
+
+                //JUMP IF TOP OF STACK IS ZERO
+                Instruction::Jz(label) => {
+                    let top = *self.stack.peek(0).ok_or(RunError::StackUnderflow)?;
+                    if top == 0 {
+                        pc = *program
+                            .labels
+                            .get(label)
+                            .ok_or_else(|| RunError::UnknownLabel(label.clone()))?;
+                        continue;
                     }
                 },
 
-                //Process the ELSE block
-                Instruction::Else(else_block) => {
-                    // This is only executed if the 'if' condition was not met,
-                    // so we don't need to check the stack again.
-                    self.run(else_block.to_vec(), path); // Pass path as an argument
+                //JUMP IF TOP OF STACK IS NON-ZERO
+                Instruction::Jnz(label) => {
+                    let top = *self.stack.peek(0).ok_or(RunError::StackUnderflow)?;
+                    if top != 0 {
+                        pc = *program
+                            .labels
+                            .get(label)
+                            .ok_or_else(|| RunError::UnknownLabel(label.clone()))?;
+                        continue;
+                    }
+                },
+
+                //DISCARD THE TOP OF STACK
+                Instruction::Pop => {
+                    self.stack.pop_back().ok_or(RunError::StackUnderflow)?;
+                },
+
+                //DUPLICATE THE TOP OF STACK
+                Instruction::Dup => {
+                    let top = *self.stack.peek(0).ok_or(RunError::StackUnderflow)?;
+                    self.push(top)?;
+                },
+
+                //SWAP THE TOP TWO STACK ENTRIES
+                Instruction::Swap => {
+                    if !self.stack.swap_with_top(1) {
+                        return Err(RunError::StackUnderflow);
+                    }
+                },
+
+                //PUSH A COPY OF THE SECOND-FROM-TOP ENTRY
+                Instruction::Over => {
+                    let second = *self.stack.peek(1).ok_or(RunError::StackUnderflow)?;
+                    self.push(second)?;
                 },
             }
             pc += 1;
         }
+        Ok(())
     }
 
-    fn load_program(reader: &mut io::BufReader<File>) -> io::Result<Vec<Instruction>> {
-        let mut program = Vec::new();
+    fn load_program(reader: &mut io::BufReader<File>) -> Result<Program, Box<dyn std::error::Error>> {
+        let mut instructions = Vec::new();
 
         // Read all lines into a vector
         let lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
@@ -162,19 +720,23 @@ impl VM {
         let mut else_block = Vec::new();
         let mut in_if_block = false;
         let mut in_else_block = false;
+        // Each IF/ELSE/ENDIF compiles to a fresh pair of labels so successive
+        // occurrences in the same file don't collide. IF blocks do not nest:
+        // the first ENDIF closes the open block.
+        let mut if_counter = 0usize;
 
         for line in lines.iter() {
             let parts: Vec<&str> = line.split_whitespace().collect();
 
             // Handle the start of an IF block
-            if parts.get(0) == Some(&"IF") {
+            if parts.first() == Some(&"IF") {
                 in_if_block = true;
                 in_else_block = false;
                 continue;
             }
 
             // Handle the start of an ELSE block
-            if parts.get(0) == Some(&"ELSE") {
+            if parts.first() == Some(&"ELSE") {
                 in_else_block = true;
                 in_if_block = false;
                 continue;
@@ -185,15 +747,29 @@ impl VM {
                 let block = if in_if_block { &mut if_block } else { &mut else_block };
 
                 // Add instruction to the current block
-                block.extend(parse_instruction(line));
+                block.extend(parse_instruction(line)?);
+
+                // Check for the end of the block: compile IF/ELSE/ENDIF down
+                // to Jz/Jmp/Label so `run` never has to recurse or re-read
+                // the source file.
+                if parts.first() == Some(&"ENDIF") {
+                    let else_label = format!("__if_else_{}", if_counter);
+                    let end_label = format!("__if_end_{}", if_counter);
+                    if_counter += 1;
 
-                // Check for the end of the block
-                if parts.get(0) == Some(&"ENDIF") {
-                    if in_if_block {
-                        program.push(Instruction::If(if_block.clone(), else_block.clone()));
+                    if else_block.is_empty() {
+                        instructions.push(Instruction::Jz(end_label.clone()));
+                        instructions.extend(if_block.clone());
+                        instructions.push(Instruction::Label(end_label));
                     } else {
-                        program.push(Instruction::Else(else_block.clone()));
+                        instructions.push(Instruction::Jz(else_label.clone()));
+                        instructions.extend(if_block.clone());
+                        instructions.push(Instruction::Jmp(end_label.clone()));
+                        instructions.push(Instruction::Label(else_label));
+                        instructions.extend(else_block.clone());
+                        instructions.push(Instruction::Label(end_label));
                     }
+
                     if_block.clear();
                     else_block.clear();
                     in_if_block = false;
@@ -204,11 +780,10 @@ impl VM {
             }
 
             // Parse other instructions
-            let instruction = parse_instruction(line);
-            program.extend(instruction);
+            instructions.extend(parse_instruction(line)?);
         }
 
-        Ok(program)
+        Ok(build_program(instructions))
     }
 }
 
@@ -224,80 +799,219 @@ fn extract_var_name(operand: &str) -> &str {
     operand.trim_start_matches("Var(\"").trim_end_matches("\")")
 }
 
-fn parse_instruction(line: &str) -> Vec<Instruction> {
+/// Parses one line of source into zero or more instructions, reporting
+/// malformed numeric operands (e.g. `PUSH abc`) as `RunError::InvalidInput`
+/// instead of panicking.
+fn parse_instruction(line: &str) -> Result<Vec<Instruction>, RunError> {
     let parts: Vec<&str> = line.split_whitespace().collect();
-    match parts.as_slice() {
-        ["PUSH", num] => vec![Instruction::Push(num.parse::<i32>().expect("Invalid number"))],
-        ["ADD", op1, op2] => {
+    let instructions = match parts.as_slice() {
+        ["PUSH", num] => vec![Instruction::Push(num.parse::<i32>().map_err(|_| {
+            RunError::InvalidInput(format!("invalid number for PUSH: {}", num))
+        })?)],
+        ["ADD"] => vec![Instruction::Add],
+        ["SUB"] => vec![Instruction::Sub],
+        ["MUL"] => vec![Instruction::Mul],
+        ["DIV"] => vec![Instruction::Div],
+        ["AND", op1, op2] => {
+            let operand1 = parse_operand(extract_var_name(op1));
+            let operand2 = parse_operand(extract_var_name(op2));
+            vec![Instruction::And(operand1, operand2)]
+        },
+        ["OR", op1, op2] => {
             let operand1 = parse_operand(extract_var_name(op1));
             let operand2 = parse_operand(extract_var_name(op2));
-            vec![Instruction::Add(operand1, operand2)]
+            vec![Instruction::Or(operand1, operand2)]
         },
-        ["SUB", op1, op2] => {
+        ["XOR", op1, op2] => {
             let operand1 = parse_operand(extract_var_name(op1));
             let operand2 = parse_operand(extract_var_name(op2));
-            vec![Instruction::Sub(operand1, operand2)]
+            vec![Instruction::Xor(operand1, operand2)]
         },
-        ["MUL", op1, op2] => {
+        ["NAND", op1, op2] => {
             let operand1 = parse_operand(extract_var_name(op1));
             let operand2 = parse_operand(extract_var_name(op2));
-            vec![Instruction::Mul(operand1, operand2)]
+            vec![Instruction::Nand(operand1, operand2)]
         },
-        ["DIV", op1, op2] => {
+        ["SHL", op1, op2] => {
             let operand1 = parse_operand(extract_var_name(op1));
             let operand2 = parse_operand(extract_var_name(op2));
-            vec![Instruction::Div(operand1, operand2)]
+            vec![Instruction::Shl(operand1, operand2)]
         },
+        ["SHR", op1, op2] => {
+            let operand1 = parse_operand(extract_var_name(op1));
+            let operand2 = parse_operand(extract_var_name(op2));
+            vec![Instruction::Shr(operand1, operand2)]
+        },
+        ["EQ", op1, op2] => {
+            let operand1 = parse_operand(extract_var_name(op1));
+            let operand2 = parse_operand(extract_var_name(op2));
+            vec![Instruction::Eq(operand1, operand2)]
+        },
+        ["LT", op1, op2] => {
+            let operand1 = parse_operand(extract_var_name(op1));
+            let operand2 = parse_operand(extract_var_name(op2));
+            vec![Instruction::Lt(operand1, operand2)]
+        },
+        ["GT", op1, op2] => {
+            let operand1 = parse_operand(extract_var_name(op1));
+            let operand2 = parse_operand(extract_var_name(op2));
+            vec![Instruction::Gt(operand1, operand2)]
+        },
+        ["CALL", name] => vec![Instruction::Call(name.to_string())],
+        ["RET"] => vec![Instruction::Ret],
         ["PRINT"] => vec![Instruction::Print],
         ["SET", var_name, value] => {
-            let value = value.parse::<i32>().expect("Invalid number");
+            let value = value.parse::<i32>().map_err(|_| {
+                RunError::InvalidInput(format!("invalid number for SET: {}", value))
+            })?;
             vec![Instruction::Set(var_name.to_string(), value)]
         },
         ["GET", var_name] => vec![Instruction::Get(var_name.to_string())],
         ["Input", var_name] => vec![Instruction::Input(var_name.to_string())],
+        ["LABEL", name] => vec![Instruction::Label(name.to_string())],
+        ["JMP", name] => vec![Instruction::Jmp(name.to_string())],
+        ["JZ", name] => vec![Instruction::Jz(name.to_string())],
+        ["JNZ", name] => vec![Instruction::Jnz(name.to_string())],
+        ["POP"] => vec![Instruction::Pop],
+        ["DUP"] => vec![Instruction::Dup],
+        ["SWAP"] => vec![Instruction::Swap],
+        ["OVER"] => vec![Instruction::Over],
         _ => vec![],
-    }
+    };
+    Ok(instructions)
 }
 
 // Function to create a BufReader and call VM::load_program
-fn load_program_and_run(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let file = match File::open(file_path) {
-        Ok(file) => file,
-        Err(e) => {
-            eprintln!("Failed to open file: {}", e);
-            return Err(Box::new(e)); // Return an error
-        }
-    };
-    let mut reader = io::BufReader::new(file);
+/// Loads a program from either a `.rm` source file or a compiled `.rvmc`
+/// bytecode file, dispatching on the bytecode magic header.
+fn load_program_from_path(path: &str) -> Result<Program, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path)?;
+    if bytes.starts_with(BYTECODE_MAGIC) {
+        Ok(build_program(load_bytecode(&bytes)?))
+    } else {
+        let mut reader = io::BufReader::new(File::open(path)?);
+        Ok(VM::load_program(&mut reader)?)
+    }
+}
 
-    // Create a VM instance
-    let mut vm = VM::new();
+fn load_program_and_run(
+    file_path: &str,
+    max_stack: usize,
+    max_steps: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program = load_program_from_path(file_path)?;
+    let mut vm = VM::with_max_stack(max_stack);
+    if let Some(max_steps) = max_steps {
+        vm = vm.with_max_steps(max_steps);
+    }
+    vm.run(&program)?; // Propagate any runtime error instead of unwinding
+    Ok(())
+}
 
-    // Load and run the program
-    match VM::load_program(&mut reader) {
-        Ok(program) => {
-            vm.run(program, file_path); // Just call run without expecting a Result
-            // Handle any other necessary logic here if needed
-        }
-        Err(e) => {
-            eprintln!("Failed to load program: {}", e);
-            return Err(Box::new(e)); // Return an error
+/// Compiles a `.rm` source file to bytecode (`rustvm compile prog.rm [-o out.rvmc]`).
+fn run_compile_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input: Option<String> = None;
+    let mut output: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" => {
+                i += 1;
+                output = args.get(i).cloned();
+            }
+            arg if input.is_none() => input = Some(arg.to_string()),
+            _ => {}
         }
+        i += 1;
     }
 
-    Ok(()) // Return Ok to indicate success
+    let input = input.ok_or("compile requires an input file")?;
+    let output = output.unwrap_or_else(|| default_bytecode_path(&input));
+
+    let mut reader = io::BufReader::new(File::open(&input)?);
+    let program = VM::load_program(&mut reader)?;
+    std::fs::write(&output, compile_program(&program.instructions))?;
+    println!("Compiled {} -> {}", input, output);
+    Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <program_file.rm>", args[0]);
-        process::exit(1);
+/// `prog.rm` -> `prog.rvmc` when no explicit `-o` is given.
+fn default_bytecode_path(input: &str) -> String {
+    match input.rsplit_once('.') {
+        Some((stem, _ext)) => format!("{}.rvmc", stem),
+        None => format!("{}.rvmc", input),
     }
+}
+
+fn print_usage(program_name: &str) {
+    eprintln!("Usage: {} <program_file.rm|.rvmc> [--stack N] [--max-steps N]", program_name);
+    eprintln!("       {} compile <program_file.rm> [-o <output.rvmc>]", program_name);
+    eprintln!("       {} run <program_file.rm|.rvmc> [--stack N] [--max-steps N]", program_name);
+}
 
-    let file_path = &args[1];
+/// Parses `<program_file> [--stack N] [--max-steps N]` and runs it; shared
+/// by the bare legacy invocation and the explicit `run` subcommand.
+fn run_program_command(args: &[String], program_name: &str) {
+    let mut file_path: Option<String> = None;
+    let mut max_stack = DEFAULT_MAX_STACK;
+    let mut max_steps: Option<u64> = None;
 
-    match load_program_and_run(file_path) {
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--stack" => {
+                i += 1;
+                let value = match args.get(i) {
+                    Some(value) => value,
+                    None => {
+                        eprintln!("--stack requires a value");
+                        process::exit(1);
+                    }
+                };
+                max_stack = match value.parse::<usize>() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        eprintln!("Invalid --stack value: {}", value);
+                        process::exit(1);
+                    }
+                };
+            }
+            "--max-steps" => {
+                i += 1;
+                let value = match args.get(i) {
+                    Some(value) => value,
+                    None => {
+                        eprintln!("--max-steps requires a value");
+                        process::exit(1);
+                    }
+                };
+                max_steps = match value.parse::<u64>() {
+                    Ok(value) => Some(value),
+                    Err(_) => {
+                        eprintln!("Invalid --max-steps value: {}", value);
+                        process::exit(1);
+                    }
+                };
+            }
+            arg if file_path.is_none() => file_path = Some(arg.to_string()),
+            _ => {
+                print_usage(program_name);
+                process::exit(1);
+            }
+        }
+        i += 1;
+    }
+
+    let file_path = match file_path {
+        Some(file_path) => file_path,
+        None => {
+            print_usage(program_name);
+            process::exit(1);
+        }
+    };
+
+    match load_program_and_run(&file_path, max_stack, max_steps) {
         Ok(_) => {
             println!("Program executed successfully.");
         }
@@ -307,3 +1021,337 @@ fn main() {
         }
     }
 }
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("compile") => {
+            if let Err(e) = run_compile_command(&args[2..]) {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+        }
+        Some("run") => run_program_command(&args[2..], &args[0]),
+        _ => run_program_command(&args[1..], &args[0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_source(source: &str) -> Result<VM, RunError> {
+        let program = build_program(instructions_from(source));
+        let mut vm = VM::new();
+        vm.run(&program)?;
+        Ok(vm)
+    }
+
+    /// Parses every line of `source`, panicking on a malformed line — for
+    /// tests that assume well-formed input and only care about runtime
+    /// behavior. Parse-error handling itself is covered separately below.
+    fn instructions_from(source: &str) -> Vec<Instruction> {
+        source
+            .lines()
+            .flat_map(|line| parse_instruction(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn division_by_zero_is_reported() {
+        let result = run_source("PUSH 1\nPUSH 0\nDIV");
+        assert_eq!(result.unwrap_err(), RunError::DivisionByZero);
+    }
+
+    #[test]
+    fn undefined_variable_is_reported() {
+        let result = run_source("GET missing");
+        assert_eq!(
+            result.unwrap_err(),
+            RunError::UndefinedVariable("missing".to_string())
+        );
+    }
+
+    #[test]
+    fn arithmetic_pops_its_operands_off_the_stack() {
+        let vm = run_source("PUSH 2\nPUSH 3\nADD\nPRINT").unwrap();
+        assert_eq!(vm.stack, vec![5]);
+    }
+
+    #[test]
+    fn arithmetic_underflows_without_enough_operands() {
+        let result = run_source("PUSH 1\nADD");
+        assert_eq!(result.unwrap_err(), RunError::StackUnderflow);
+    }
+
+    #[test]
+    fn overflowing_arithmetic_is_reported_not_panicked() {
+        assert_eq!(
+            run_source(&format!("PUSH {}\nPUSH 1\nADD", i32::MAX)).unwrap_err(),
+            RunError::ArithmeticOverflow
+        );
+        assert_eq!(
+            run_source(&format!("PUSH {}\nPUSH 1\nSUB", i32::MIN)).unwrap_err(),
+            RunError::ArithmeticOverflow
+        );
+        assert_eq!(
+            run_source("PUSH 2000000000\nPUSH 2000000000\nMUL").unwrap_err(),
+            RunError::ArithmeticOverflow
+        );
+        assert_eq!(
+            run_source(&format!("PUSH {}\nPUSH -1\nDIV", i32::MIN)).unwrap_err(),
+            RunError::ArithmeticOverflow
+        );
+    }
+
+    #[test]
+    fn forward_jump_skips_block() {
+        // Jz should skip straight to the label when the top of stack is zero.
+        let vm = run_source("PUSH 0\nJZ end\nPUSH 111\nLABEL end\nPUSH 222").unwrap();
+        assert_eq!(vm.stack, vec![0, 222]);
+    }
+
+    #[test]
+    fn backward_jump_runs_loop_body_then_exits() {
+        // "once" starts truthy, the loop body flips it to 0 and jumps back
+        // to the top, and the second pass through the Jz sees 0 and exits.
+        let program = "SET once 1\n\
+                        LABEL loop\n\
+                        GET once\n\
+                        JZ done\n\
+                        PUSH 7\n\
+                        SET once 0\n\
+                        JMP loop\n\
+                        LABEL done\n\
+                        PUSH 99";
+        let vm = run_source(program).unwrap();
+        assert_eq!(vm.stack, vec![1, 7, 0, 99]);
+    }
+
+    #[test]
+    fn jump_to_missing_label_is_reported() {
+        let result = run_source("JMP nowhere");
+        assert_eq!(result.unwrap_err(), RunError::UnknownLabel("nowhere".to_string()));
+    }
+
+    #[test]
+    fn push_past_max_stack_overflows() {
+        let instructions = instructions_from("PUSH 1\nPUSH 1\nPUSH 1");
+        let program = build_program(instructions);
+        let mut vm = VM::with_max_stack(2);
+        assert_eq!(vm.run(&program).unwrap_err(), RunError::StackOverflow);
+        assert_eq!(vm.stack.len(), 2);
+    }
+
+    #[test]
+    fn max_stack_is_capped_at_the_hard_limit() {
+        let vm = VM::with_max_stack(1_000_000);
+        assert_eq!(vm.max_stack, MAX_STACK_LIMIT);
+    }
+
+    #[test]
+    fn dup_duplicates_the_top_of_stack() {
+        let vm = run_source("PUSH 5\nDUP").unwrap();
+        assert_eq!(vm.stack, vec![5, 5]);
+    }
+
+    #[test]
+    fn swap_exchanges_the_top_two_entries() {
+        let vm = run_source("PUSH 1\nPUSH 2\nSWAP").unwrap();
+        assert_eq!(vm.stack, vec![2, 1]);
+    }
+
+    #[test]
+    fn over_pushes_a_copy_of_the_second_entry() {
+        let vm = run_source("PUSH 1\nPUSH 2\nOVER").unwrap();
+        assert_eq!(vm.stack, vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn pop_removes_the_top_of_stack() {
+        let vm = run_source("PUSH 1\nPUSH 2\nPOP").unwrap();
+        assert_eq!(vm.stack, vec![1]);
+    }
+
+    #[test]
+    fn pop_on_empty_stack_underflows() {
+        let result = run_source("POP");
+        assert_eq!(result.unwrap_err(), RunError::StackUnderflow);
+    }
+
+    #[test]
+    fn bitwise_operators_compute_expected_values() {
+        assert_eq!(run_source("AND 6 3").unwrap().stack, vec![6 & 3]);
+        assert_eq!(run_source("OR 6 3").unwrap().stack, vec![6 | 3]);
+        assert_eq!(run_source("XOR 6 3").unwrap().stack, vec![6 ^ 3]);
+        assert_eq!(run_source("NAND 6 3").unwrap().stack, vec![!(6 & 3)]);
+        assert_eq!(run_source("SHL 1 4").unwrap().stack, vec![1 << 4]);
+        assert_eq!(run_source("SHR 16 2").unwrap().stack, vec![16 >> 2]);
+    }
+
+    #[test]
+    fn comparison_operators_push_one_or_zero() {
+        assert_eq!(run_source("EQ 3 3").unwrap().stack, vec![1]);
+        assert_eq!(run_source("EQ 3 4").unwrap().stack, vec![0]);
+        assert_eq!(run_source("LT 3 4").unwrap().stack, vec![1]);
+        assert_eq!(run_source("LT 4 3").unwrap().stack, vec![0]);
+        assert_eq!(run_source("GT 4 3").unwrap().stack, vec![1]);
+        assert_eq!(run_source("GT 3 4").unwrap().stack, vec![0]);
+    }
+
+    #[test]
+    fn comparisons_compose_with_conditional_jumps() {
+        // LT pushes 0 for "3 < 2", so Jz should take the branch to `false`.
+        let vm = run_source("LT 3 2\nJZ false\nPUSH 1\nJMP end\nLABEL false\nPUSH 0\nLABEL end").unwrap();
+        assert_eq!(vm.stack, vec![0, 0]);
+    }
+
+    #[test]
+    fn call_and_ret_return_to_the_instruction_after_call() {
+        let vm = run_source(
+            "CALL add_one\n\
+             JMP end\n\
+             LABEL add_one\n\
+             PUSH 1\n\
+             RET\n\
+             LABEL end",
+        )
+        .unwrap();
+        assert_eq!(vm.stack, vec![1]);
+    }
+
+    #[test]
+    fn nested_calls_unwind_in_the_right_order() {
+        let vm = run_source(
+            "CALL outer\n\
+             JMP end\n\
+             LABEL outer\n\
+             PUSH 1\n\
+             CALL inner\n\
+             PUSH 3\n\
+             RET\n\
+             LABEL inner\n\
+             PUSH 2\n\
+             RET\n\
+             LABEL end",
+        )
+        .unwrap();
+        assert_eq!(vm.stack, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn self_recursive_call_stops_at_its_base_case() {
+        // `fact` calls itself once more only while `more` is non-zero, clearing
+        // `more` before the recursive call so the second invocation hits the
+        // base case and returns without recursing again.
+        let vm = run_source(
+            "SET more 1\n\
+             CALL fact\n\
+             JMP end\n\
+             LABEL fact\n\
+             GET more\n\
+             JZ base\n\
+             SET more 0\n\
+             CALL fact\n\
+             LABEL base\n\
+             RET\n\
+             LABEL end",
+        )
+        .unwrap();
+        assert_eq!(vm.stack, vec![1, 0]);
+        assert_eq!(vm.vars.get("more"), Some(&0));
+    }
+
+    #[test]
+    fn unbounded_recursion_is_stopped_by_the_call_depth_guard() {
+        let instructions = instructions_from("LABEL loop\nCALL loop");
+        let program = build_program(instructions);
+        let mut vm = VM::new();
+        assert_eq!(vm.run(&program).unwrap_err(), RunError::CallStackOverflow);
+    }
+
+    #[test]
+    fn ret_without_a_matching_call_is_a_stack_underflow() {
+        let result = run_source("RET");
+        assert_eq!(result.unwrap_err(), RunError::StackUnderflow);
+    }
+
+    #[test]
+    fn malformed_push_operand_is_reported_not_panicked() {
+        assert_eq!(
+            parse_instruction("PUSH abc").unwrap_err(),
+            RunError::InvalidInput("invalid number for PUSH: abc".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_set_operand_is_reported_not_panicked() {
+        assert_eq!(
+            parse_instruction("SET x abc").unwrap_err(),
+            RunError::InvalidInput("invalid number for SET: abc".to_string())
+        );
+    }
+
+    #[test]
+    fn bytecode_round_trips_every_instruction() {
+        let source = "PUSH 3\n\
+                       ADD\n\
+                       SUB\n\
+                       MUL\n\
+                       DIV\n\
+                       SET x 5\n\
+                       GET x\n\
+                       Input y\n\
+                       LABEL loop\n\
+                       JMP loop\n\
+                       JZ loop\n\
+                       JNZ loop\n\
+                       POP\n\
+                       DUP\n\
+                       SWAP\n\
+                       OVER\n\
+                       AND 1 2\n\
+                       OR 1 2\n\
+                       XOR 1 2\n\
+                       NAND 1 2\n\
+                       SHL 1 2\n\
+                       SHR 1 2\n\
+                       EQ 1 2\n\
+                       LT 1 2\n\
+                       GT 1 2\n\
+                       CALL sub\n\
+                       RET\n\
+                       PRINT";
+        let instructions = instructions_from(source);
+        let bytecode = compile_program(&instructions);
+        let round_tripped = load_bytecode(&bytecode).unwrap();
+        assert_eq!(instructions, round_tripped);
+    }
+
+    #[test]
+    fn loading_bytecode_with_bad_magic_is_reported() {
+        let result = load_bytecode(b"nope");
+        assert_eq!(
+            result.unwrap_err(),
+            RunError::InvalidInput("not a rustvm bytecode file".to_string())
+        );
+    }
+
+    #[test]
+    fn infinite_loop_runs_out_of_gas() {
+        let instructions = instructions_from("LABEL loop\nPUSH 1\nJMP loop");
+        let program = build_program(instructions);
+        let mut vm = VM::new().with_max_steps(100);
+        assert_eq!(vm.run(&program).unwrap_err(), RunError::OutOfGas);
+    }
+
+    #[test]
+    fn finite_program_completes_within_budget() {
+        let instructions = instructions_from("PUSH 1\nPUSH 2\nADD");
+        let program = build_program(instructions);
+        let mut vm = VM::new().with_max_steps(10);
+        vm.run(&program).unwrap();
+        assert_eq!(vm.stack, vec![3]);
+    }
+}